@@ -0,0 +1,4 @@
+pub mod tcp;
+
+#[cfg(feature = "quic")]
+pub mod quic;
@@ -0,0 +1,11 @@
+//! Optional QUIC transport, mirroring the `tcp` module's reader surface.
+//!
+//! Gated behind the `quic` feature since it pulls in quinn-proto, which
+//! deployments that only ever speak to LAN/WAN TCP peers shouldn't have to
+//! pay the dependency cost for.
+
+#[cfg(feature = "quic")]
+pub mod reader;
+
+#[cfg(feature = "quic")]
+pub use self::reader::{QuicReader, QuicReaderCMD, QuicReaderCommand};
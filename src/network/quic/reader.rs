@@ -0,0 +1,472 @@
+#![allow(dead_code)]
+extern crate bytes;
+extern crate mio;
+extern crate quinn_proto;
+
+use network::tcp::{TcpNetworkCommand, TcpNetworkCMD};
+use std::collections::HashMap;
+use std::io::Result;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use self::bytes::Bytes;
+use self::mio::{Poll, Token, Ready, PollOpt, Events};
+use self::mio::channel::{Receiver, Sender, channel};
+use self::mio::udp::UdpSocket;
+use self::quinn_proto::{ClientConfig, Connection, ConnectionHandle, DatagramEvent, Dir, Endpoint, EndpointEvent, Event, StreamEvent, StreamId, VarInt, WriteError};
+
+/// Large enough for a max-size UDP datagram on any path MTU we'll see.
+const READER_READ_BUFFER_SIZE: usize = 65536;
+const READER_CHANNEL_TOKEN: Token = Token(1);
+const ENDPOINT_SOCKET_TOKEN: Token = Token(2);
+
+/// How long until `deadline`, clamped to zero for deadlines already passed,
+/// so a connection whose timer already elapsed doesn't block `poll` forever
+/// waiting on a negative duration.
+#[inline(always)]
+fn time_until(now: Instant, deadline: Instant) -> Duration {
+    deadline.saturating_duration_since(now)
+}
+
+pub enum QuicReaderCMD {
+    HandleNewConnection,
+    CloseConnection,
+    SendData,
+}
+
+pub struct QuicReaderCommand {
+    // base command code
+    code: QuicReaderCMD,
+    // peer address vector for dialing a new QUIC connection
+    addr: Vec<SocketAddr>,
+    token: Vec<Token>,
+    data: Vec<Arc<Vec<u8>>>
+}
+
+/// One TreeScale connection, backed by a single QUIC connection. Each framed
+/// message TreeScale sends maps to its own QUIC stream, so a stalled message
+/// can't head-of-line block the others the way one TCP connection would.
+struct QuicReaderConn {
+    handle: ConnectionHandle,
+    connection: Connection,
+    // buffers queued by `SendData`, each opened on its own outgoing stream
+    write_queue: Vec<Arc<Vec<u8>>>,
+    // streams currently being written out: the stream quinn-proto opened for
+    // each buffer, the buffer itself, and how much of it flow control has
+    // accepted so far. A stream only gets `finish()`ed once its whole buffer
+    // has been written, since `SendStream::write` only accepts as many bytes
+    // as the current flow-control window allows and must be retried across
+    // `drain_endpoint` calls for anything bigger.
+    sending: Vec<(StreamId, Arc<Vec<u8>>, usize)>,
+}
+
+/// QUIC counterpart to `TcpReader`.
+///
+/// Exposes the same `HandleNewConnection` / `CloseConnection` / `SendData`
+/// command surface and the same `TcpNetworkCommand` feedback events as
+/// `TcpReader`, so `TcpNetwork`'s routing and framing code paths work
+/// unchanged regardless of which transport backs a given token.
+///
+/// This is built on `quinn-proto`, not the high-level `quinn` crate: quinn
+/// owns its socket and timers via its own tokio task, which makes it
+/// impossible to drive from a foreign mio 0.6 poll loop. `quinn-proto` is
+/// sans-IO — `Endpoint` and `Connection` only produce and consume bytes,
+/// events and deadlines, and own no socket or timer themselves — so this
+/// reader is the one pumping UDP datagrams between it and the socket, and
+/// waking its per-connection timers, from the same mio poll loop `TcpReader`
+/// uses. `poll.poll`'s timeout is clamped to the soonest connection deadline
+/// so we wake in time to drive it even with no socket traffic.
+pub struct QuicReader {
+    endpoint: Endpoint,
+    client_config: ClientConfig,
+    socket: UdpSocket,
+    // keyed by the mio Token handed to us in commands, same as TcpReader's
+    // connection slab
+    connections: HashMap<Token, QuicReaderConn>,
+    // incoming datagrams are demultiplexed by quinn-proto's own
+    // ConnectionHandle, so we need the reverse mapping back to our Token
+    handles: HashMap<ConnectionHandle, Token>,
+
+    // buffer for making one time allocation per read process
+    datagram: Vec<u8>,
+
+    // base event loop handler
+    poll: Poll,
+
+    // chanel sender, receiver for keeping communication with loop
+    channel_sender: Sender<QuicReaderCommand>,
+    channel_receiver: Receiver<QuicReaderCommand>,
+
+    // channel for sending commands to TcpNetwork main loop
+    channel_tcp_net: Sender<TcpNetworkCommand>
+}
+
+impl QuicReader {
+    /// Creates a new QuicReader driving the given quinn-proto endpoint over
+    /// the given UDP socket, registering the socket with a fresh poll
+    /// instance. `client_config` is used for every outbound `connect`.
+    pub fn new(tcp_net_chan: Sender<TcpNetworkCommand>, endpoint: Endpoint, client_config: ClientConfig, socket: UdpSocket) -> Result<QuicReader> {
+        let (s, r) = channel::<QuicReaderCommand>();
+        let poll = Poll::new()?;
+        poll.register(&socket, ENDPOINT_SOCKET_TOKEN, Ready::readable(), PollOpt::edge())?;
+
+        Ok(QuicReader {
+            endpoint: endpoint,
+            client_config: client_config,
+            socket: socket,
+            connections: HashMap::new(),
+            handles: HashMap::new(),
+            datagram: vec![0; READER_READ_BUFFER_SIZE],
+            poll: poll,
+            channel_sender: s,
+            channel_receiver: r,
+            channel_tcp_net: tcp_net_chan
+        })
+    }
+
+    /// Clonning channel for sending commands
+    pub fn channel(&self) -> Sender<QuicReaderCommand> {
+        self.channel_sender.clone()
+    }
+
+    /// Private function for handling Reader commands
+    #[inline(always)]
+    fn notify(&mut self, cmd: &mut QuicReaderCommand) {
+        match cmd.code {
+            QuicReaderCMD::HandleNewConnection => {
+                // Handling new connection with given address
+                // if it exists in Vector of addresses
+                while !cmd.addr.is_empty() && !cmd.token.is_empty() {
+                    let addr = match cmd.addr.pop() {
+                        Some(a) => a,
+                        None => return
+                    };
+
+                    let token = match cmd.token.pop() {
+                        Some(t) => t,
+                        None => return
+                    };
+
+                    let (handle, connection) = match self.endpoint.connect(self.client_config.clone(), addr, "treescale") {
+                        Ok(r) => r,
+                        Err(_) => continue
+                    };
+
+                    self.handles.insert(handle, token);
+                    self.connections.insert(token, QuicReaderConn {
+                        handle: handle,
+                        connection: connection,
+                        write_queue: Vec::new(),
+                        sending: Vec::new()
+                    });
+                }
+
+                self.drain_endpoint();
+            }
+
+            QuicReaderCMD::CloseConnection => {
+                // Closing connection by given token
+                while !cmd.token.is_empty() {
+                    let token = match cmd.token.pop() {
+                        Some(t) => t,
+                        _ => return
+                    };
+
+                    self.close_connection(token, false);
+                }
+            }
+
+            QuicReaderCMD::SendData => {
+                // if data is empty just returning
+                if cmd.data.len() == 0 {
+                    return;
+                }
+
+                while !cmd.token.is_empty() {
+                    let token = match cmd.token.pop() {
+                        Some(t) => t,
+                        _ => return
+                    };
+
+                    // if we have this connection
+                    // adding sent data to its queue, one QUIC stream per frame
+                    let conn = match self.connections.get_mut(&token) {
+                        Some(conn) => conn,
+                        None => continue
+                    };
+
+                    conn.write_queue.append(&mut cmd.data);
+                }
+
+                self.drain_endpoint();
+            }
+        }
+    }
+
+    /// running QuicReader loop
+    /// this will exit when loop is no longer running
+    pub fn run(&mut self) -> Result<()> {
+        // registering receiver for poll loop
+        self.poll.register(&self.channel_receiver, READER_CHANNEL_TOKEN, Ready::readable(), PollOpt::edge())?;
+
+        let mut events: Events = Events::with_capacity(1000);
+
+        loop {
+            // clamp the wait to the soonest connection deadline so quinn-proto
+            // still gets driven even when no datagram arrives in time
+            let timeout = self.next_timeout();
+            let event_count = self.poll.poll(&mut events, timeout).unwrap();
+
+            if event_count == 0 {
+                // nothing arrived before the deadline; let quinn-proto react
+                self.drive_timeouts();
+                continue;
+            }
+
+            for event in events.iter() {
+                let token = event.token();
+
+                if token == READER_CHANNEL_TOKEN {
+                    match self.channel_receiver.try_recv() {
+                        Ok(cmd) => {
+                            let mut c = cmd;
+                            self.notify(&mut c);
+                        }
+                        Err(_) => {}
+                    }
+                    continue;
+                }
+
+                if token == ENDPOINT_SOCKET_TOKEN {
+                    self.pump_socket();
+                    continue;
+                }
+            }
+        }
+    }
+
+    /// Reads every datagram currently queued on the UDP socket and hands
+    /// each one to `Endpoint::handle`, which demultiplexes it to the right
+    /// connection by `ConnectionHandle`.
+    ///
+    /// A brand new inbound connection handshake is ignored here: this reader
+    /// only carries connections this side dialed via `HandleNewConnection`,
+    /// same as `TcpReader` only ever receives sockets `TcpNetwork` already
+    /// accepted elsewhere.
+    #[inline(always)]
+    fn pump_socket(&mut self) {
+        loop {
+            let (read, from) = match self.socket.recv_from(&mut self.datagram) {
+                Ok(Some(r)) => r,
+                Ok(None) => break,
+                Err(_) => break
+            };
+
+            let now = Instant::now();
+            match self.endpoint.handle(now, from, None, None, self.datagram[..read].into()) {
+                Some((handle, DatagramEvent::ConnectionEvent(event))) => {
+                    let token = match self.handles.get(&handle) {
+                        Some(t) => *t,
+                        None => continue
+                    };
+
+                    if let Some(conn) = self.connections.get_mut(&token) {
+                        conn.connection.handle_event(event);
+                    }
+                }
+                Some((_, DatagramEvent::NewConnection(_))) => {
+                    // unsolicited inbound handshake with no corresponding
+                    // token; nothing in TcpNetwork would know how to route it
+                }
+                None => {}
+            }
+        }
+
+        self.drain_endpoint();
+    }
+
+    /// Lets quinn-proto react to any connection whose retransmission or idle
+    /// timer has elapsed.
+    #[inline(always)]
+    fn drive_timeouts(&mut self) {
+        let now = Instant::now();
+        for conn in self.connections.values_mut() {
+            conn.connection.handle_timeout(now);
+        }
+
+        self.drain_endpoint();
+    }
+
+    /// Drives every connection's state machine forward: pulls application
+    /// events (stream data, handshake completion, loss of connection) off
+    /// each `Connection`, opens a stream for anything queued by `SendData`,
+    /// flushes whatever outgoing datagrams that produced onto the socket,
+    /// and forwards `EndpointEvent`s each connection emitted back to the
+    /// shared `Endpoint` (and anything it hands back from that, back to the
+    /// connection) so CID retirement and reset tokens stay in sync the way
+    /// quinn-proto's sans-IO contract requires.
+    #[inline(always)]
+    fn drain_endpoint(&mut self) {
+        let now = Instant::now();
+        let mut lost: Vec<Token> = Vec::new();
+        let mut endpoint_events: Vec<(ConnectionHandle, EndpointEvent)> = Vec::new();
+
+        for (&token, conn) in self.connections.iter_mut() {
+            while let Some(event) = conn.connection.poll() {
+                match event {
+                    Event::Stream(StreamEvent::Readable { id }) => {
+                        let mut recv = conn.connection.recv_stream(id);
+                        let mut chunks = match recv.read(true) {
+                            Ok(chunks) => chunks,
+                            Err(_) => continue
+                        };
+
+                        while let Ok(Some(chunk)) = chunks.next(READER_READ_BUFFER_SIZE) {
+                            let _ = self.channel_tcp_net.send(TcpNetworkCommand {
+                                cmd: TcpNetworkCMD::HandleNewData,
+                                token: token,
+                                data: vec![Arc::new(chunk.bytes.to_vec())]
+                            });
+                        }
+
+                        let _ = chunks.finalize();
+                    }
+                    Event::ConnectionLost { .. } => lost.push(token),
+                    _ => {}
+                }
+            }
+
+            // open a stream for everything newly queued by `SendData` that
+            // quinn-proto currently has stream credit for; anything left
+            // over stays in `write_queue` and is retried on a later call
+            while !conn.write_queue.is_empty() {
+                let id = match conn.connection.streams().open(Dir::Uni) {
+                    Some(id) => id,
+                    None => break
+                };
+
+                let buf = conn.write_queue.remove(0);
+                conn.sending.push((id, buf, 0));
+            }
+
+            // retry every stream still being written, since a send only
+            // accepts as many bytes as the current flow-control window
+            // allows; only finish() a stream once its whole buffer has gone
+            // out, so a large payload is never truncated and marked done
+            let mut finished = Vec::new();
+            for (i, &mut (id, ref buf, ref mut written)) in conn.sending.iter_mut().enumerate() {
+                loop {
+                    if *written >= buf.len() {
+                        finished.push(i);
+                        break;
+                    }
+
+                    let mut send = conn.connection.send_stream(id);
+                    match send.write(&buf[*written..]) {
+                        Ok(n) => *written += n,
+                        Err(WriteError::Blocked) => break,
+                        Err(_) => {
+                            // stream reset out from under us; give up on it
+                            // rather than retrying forever
+                            finished.push(i);
+                            break;
+                        }
+                    }
+                }
+            }
+
+            for &i in finished.iter().rev() {
+                let (id, _, _) = conn.sending.remove(i);
+                let _ = conn.connection.send_stream(id).finish();
+            }
+
+            while let Some(transmit) = conn.connection.poll_transmit(now, 1) {
+                let _ = self.socket.send_to(&transmit.contents, &transmit.destination);
+            }
+
+            while let Some(event) = conn.connection.poll_endpoint_events() {
+                endpoint_events.push((conn.handle, event));
+            }
+        }
+
+        for (handle, event) in endpoint_events {
+            let returned = match self.endpoint.handle_event(handle, event) {
+                Some(e) => e,
+                None => continue
+            };
+
+            let token = match self.handles.get(&handle) {
+                Some(t) => *t,
+                None => continue
+            };
+
+            if let Some(conn) = self.connections.get_mut(&token) {
+                conn.connection.handle_event(returned);
+            }
+        }
+
+        for token in lost {
+            self.close_connection(token, true);
+        }
+    }
+
+    /// Soonest deadline across every open connection, or `None` if there are
+    /// no connections to wait on.
+    #[inline(always)]
+    fn next_timeout(&mut self) -> Option<Duration> {
+        let now = Instant::now();
+        self.connections.values_mut()
+            .filter_map(|conn| conn.connection.poll_timeout())
+            .map(|deadline| time_until(now, deadline))
+            .min()
+    }
+
+    #[inline(always)]
+    fn close_connection(&mut self, token: Token, send_data_event: bool) {
+        let mut conn = match self.connections.remove(&token) {
+            Some(c) => c,
+            None => return
+        };
+
+        self.handles.remove(&conn.handle);
+
+        // send CONNECTION_CLOSE instead of just dropping the state, so the
+        // peer doesn't have to wait out its idle timeout to notice we're gone
+        let reason: Bytes = Bytes::new();
+        conn.connection.close(Instant::now(), VarInt::from_u32(0), reason);
+        while let Some(transmit) = conn.connection.poll_transmit(Instant::now(), 1) {
+            let _ = self.socket.send_to(&transmit.contents, &transmit.destination);
+        }
+
+        // do we need to send event about connection close to
+        // connection handler loop or not
+        if send_data_event {
+            let _ = self.channel_tcp_net.send(TcpNetworkCommand {
+                cmd: TcpNetworkCMD::ConnectionClosed,
+                token: token,
+                data: Vec::new()
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `QuicReader::new` needs a live quinn-proto `Endpoint` and `ClientConfig`,
+    // which in turn need a TLS crypto config; wiring that up for a real
+    // handshake is out of scope for a unit test and isn't covered here. This
+    // exercises the one piece of `next_timeout`'s logic that doesn't need a
+    // live `Connection` to construct: clamping an already-elapsed deadline to
+    // zero rather than going negative.
+    #[test]
+    fn time_until_clamps_elapsed_deadlines_to_zero() {
+        let now = Instant::now();
+        let past = now - Duration::from_secs(1);
+        let future = now + Duration::from_millis(50);
+
+        assert_eq!(time_until(now, past), Duration::from_secs(0));
+        assert_eq!(time_until(now, future), Duration::from_millis(50));
+    }
+}
@@ -0,0 +1,152 @@
+/// A view over a connection's accumulation buffer, handed to a `FrameParser`
+/// so it can inspect and consume bytes without owning the buffer itself.
+pub struct BytesIn<'a> {
+    buf: &'a mut Vec<u8>,
+}
+
+impl<'a> BytesIn<'a> {
+    pub fn new(buf: &'a mut Vec<u8>) -> BytesIn<'a> {
+        BytesIn { buf: buf }
+    }
+
+    /// Number of bytes currently buffered.
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Whether no bytes are currently buffered.
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// Borrows the first `n` bytes without consuming them, or `None` if
+    /// fewer than `n` bytes are available yet.
+    pub fn peek(&self, n: usize) -> Option<&[u8]> {
+        if self.buf.len() < n {
+            return None;
+        }
+
+        Some(&self.buf[..n])
+    }
+
+    /// Removes and returns the first `n` bytes. Panics if fewer than `n`
+    /// bytes are buffered; callers must `peek` or `len` first.
+    pub fn consume(&mut self, n: usize) -> Vec<u8> {
+        self.buf.drain(..n).collect()
+    }
+}
+
+/// Outcome of feeding buffered bytes through a `FrameParser`.
+pub enum ParseResult {
+    // not enough bytes buffered yet to complete a frame
+    NeedMore,
+    // a complete frame, already removed from the buffer
+    Frame(Vec<u8>),
+    // the buffered bytes can never form a valid frame
+    Error,
+}
+
+/// Pluggable wire framing for `TcpReader`.
+///
+/// A parser is handed the connection's accumulation buffer after every socket
+/// read and is called in a loop until it returns `NeedMore` or `Error`. Each
+/// call consumes and returns at most one complete frame, leaving any trailing
+/// partial frame buffered for a later call.
+pub trait FrameParser {
+    fn parse(&mut self, buf: &mut BytesIn) -> ParseResult;
+}
+
+/// Length, in bytes, of the default wire format's frame length prefix.
+const LENGTH_PREFIX_SIZE: usize = 4;
+
+/// The built-in framing every existing TreeScale peer speaks: a u32
+/// big-endian length prefix followed by that many payload bytes.
+#[derive(Clone)]
+pub struct LengthPrefixParser;
+
+impl FrameParser for LengthPrefixParser {
+    fn parse(&mut self, buf: &mut BytesIn) -> ParseResult {
+        let header = match buf.peek(LENGTH_PREFIX_SIZE) {
+            Some(h) => h,
+            None => return ParseResult::NeedMore
+        };
+
+        let frame_len = ((header[0] as usize) << 24)
+            | ((header[1] as usize) << 16)
+            | ((header[2] as usize) << 8)
+            | (header[3] as usize);
+
+        if buf.len() < LENGTH_PREFIX_SIZE + frame_len {
+            return ParseResult::NeedMore;
+        }
+
+        buf.consume(LENGTH_PREFIX_SIZE);
+        ParseResult::Frame(buf.consume(frame_len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_all(parser: &mut LengthPrefixParser, buf: &mut Vec<u8>) -> ParseResult {
+        let mut bytes_in = BytesIn::new(buf);
+        parser.parse(&mut bytes_in)
+    }
+
+    #[test]
+    fn length_header_split_across_two_calls_needs_more() {
+        let mut parser = LengthPrefixParser;
+
+        // only the first two bytes of the length prefix have arrived
+        let mut buf = vec![0, 0];
+        match parse_all(&mut parser, &mut buf) {
+            ParseResult::NeedMore => {}
+            _ => panic!("expected NeedMore with a partial length header")
+        }
+        // nothing should have been consumed while waiting for the rest
+        assert_eq!(buf, vec![0, 0]);
+
+        // the rest of the header arrives, but no payload yet
+        buf.extend_from_slice(&[0, 5]);
+        match parse_all(&mut parser, &mut buf) {
+            ParseResult::NeedMore => {}
+            _ => panic!("expected NeedMore with no payload buffered")
+        }
+        assert_eq!(buf.len(), 4);
+
+        // the payload finally arrives
+        buf.extend_from_slice(b"hello");
+        match parse_all(&mut parser, &mut buf) {
+            ParseResult::Frame(frame) => assert_eq!(frame, b"hello".to_vec()),
+            _ => panic!("expected a complete frame once the payload arrived")
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn zero_length_frame_parses_immediately() {
+        let mut parser = LengthPrefixParser;
+        let mut buf = vec![0, 0, 0, 0];
+
+        match parse_all(&mut parser, &mut buf) {
+            ParseResult::Frame(frame) => assert!(frame.is_empty()),
+            _ => panic!("expected an empty frame for a zero length prefix")
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn exact_boundary_buffer_parses_without_needing_more() {
+        let mut parser = LengthPrefixParser;
+        // header + payload with nothing trailing: buffer ends exactly at the
+        // frame boundary
+        let mut buf = vec![0, 0, 0, 3, b'a', b'b', b'c'];
+
+        match parse_all(&mut parser, &mut buf) {
+            ParseResult::Frame(frame) => assert_eq!(frame, b"abc".to_vec()),
+            _ => panic!("expected a complete frame at the exact buffer boundary")
+        }
+        assert!(buf.is_empty());
+    }
+}
@@ -0,0 +1,34 @@
+extern crate mio;
+
+mod conn;
+pub mod frame_parser;
+pub mod reader;
+
+use std::sync::Arc;
+use self::mio::Token;
+
+pub use self::conn::TcpReaderConn;
+pub use self::frame_parser::{BytesIn, FrameParser, LengthPrefixParser, ParseResult};
+
+/// Commands sent from a `TcpReader` back to the `TcpNetwork` main loop.
+pub enum TcpNetworkCMD {
+    // a reader finished assembling one or more frames for a connection
+    HandleNewData,
+    // a connection was closed, either by the peer or by us
+    ConnectionClosed,
+    // a reader was already at its `max_connections` cap and closed the
+    // incoming socket instead of accepting it
+    ConnectionRejected,
+    // a `SendData` batch would have pushed a connection's write queue past
+    // its high-water mark; that batch was dropped and the producer should pause
+    Backpressure,
+}
+
+pub struct TcpNetworkCommand {
+    // base command code
+    pub cmd: TcpNetworkCMD,
+    // connection this command is about
+    pub token: Token,
+    // frames carried by `HandleNewData`, empty for other commands
+    pub data: Vec<Arc<Vec<u8>>>
+}
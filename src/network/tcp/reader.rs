@@ -3,12 +3,12 @@
 extern crate mio;
 
 use network::tcp::{TcpReaderConn, TcpNetworkCommand, TcpNetworkCMD};
+use network::tcp::frame_parser::{FrameParser, LengthPrefixParser};
 use std::io::Result;
 use self::mio::{Poll, Token, Ready, PollOpt, Events};
 use self::mio::channel::{Receiver, Sender, channel};
 use self::mio::tcp::TcpStream;
 use std::sync::Arc;
-use std::collections::BTreeMap;
 
 /// Read buffer size 64KB
 const READER_READ_BUFFER_SIZE: usize = 65000;
@@ -29,15 +29,31 @@ pub struct TcpReaderCommand {
     data: Vec<Arc<Vec<u8>>>
 }
 
-pub struct TcpReader {
-    // connections transferred to this reader for IO operations
-    connections: Vec<TcpReaderConn>,
-    // map for keeping vector keys based on connections
-    // beacuse we are getting events based on connection keys
-    connection_keys: BTreeMap<Token, usize>,
-
-    // buffers for making one time allocations per read process
-    data_len_buf: Vec<u8>,
+/// Reads frames off a set of transferred TCP sockets and pushes completed
+/// frames and lifecycle events back to `TcpNetwork`.
+///
+/// Generic over `P` so the wire format isn't hardwired: `P` is cloned into
+/// each new connection, letting per-connection framing state (e.g. a partial
+/// frame) live independently of every other connection.
+pub struct TcpReader<P: FrameParser + Clone> {
+    // connections transferred to this reader for IO operations, slab style:
+    // a connection's own Token doubles as its index, so closing one can never
+    // shift another connection's index out from under it
+    connections: Vec<Option<TcpReaderConn<P>>>,
+
+    // number of connections currently held open, tracked incrementally so
+    // load-balancing decisions don't have to rescan the slab
+    open_connections: usize,
+    // connections beyond this count are rejected rather than accepted
+    max_connections: usize,
+    // a connection's write queue is not allowed to grow past this many
+    // queued bytes
+    write_high_water_mark: usize,
+
+    // template cloned into every new connection's own parser instance
+    parser: P,
+
+    // buffer for making one time allocation per read process
     data_chunk: Vec<u8>,
 
     // base event loop handler
@@ -51,19 +67,30 @@ pub struct TcpReader {
     channel_tcp_net: Sender<TcpNetworkCommand>
 }
 
-impl TcpReader {
-    /// creating new TcpReader with default values
-    pub fn new(tcp_net_chan: Sender<TcpNetworkCommand>) -> TcpReader {
-        let (s, r)= channel::<TcpReaderCommand>();
+impl TcpReader<LengthPrefixParser> {
+    /// Creates a new TcpReader speaking the built-in u32 length-prefixed wire
+    /// format that every existing TreeScale peer speaks.
+    pub fn new(tcp_net_chan: Sender<TcpNetworkCommand>, max_connections: usize, write_high_water_mark: usize) -> TcpReader<LengthPrefixParser> {
+        TcpReader::with_parser(tcp_net_chan, LengthPrefixParser, max_connections, write_high_water_mark)
+    }
+}
+
+impl<P: FrameParser + Clone> TcpReader<P> {
+    /// Creates a new TcpReader using a caller-supplied frame parser, for
+    /// peers that don't speak the default length-prefixed protocol.
+    pub fn with_parser(tcp_net_chan: Sender<TcpNetworkCommand>, parser: P, max_connections: usize, write_high_water_mark: usize) -> TcpReader<P> {
+        let (s, r) = channel::<TcpReaderCommand>();
         TcpReader {
             connections: Vec::new(),
-            data_len_buf: vec![0; 4],
+            open_connections: 0,
+            max_connections: max_connections,
+            write_high_water_mark: write_high_water_mark,
+            parser: parser,
             data_chunk: vec![0; READER_READ_BUFFER_SIZE],
             poll: Poll::new().unwrap(),
             channel_sender: s,
             channel_receiver: r,
-            channel_tcp_net: tcp_net_chan,
-            connection_keys: BTreeMap::new()
+            channel_tcp_net: tcp_net_chan
         }
     }
 
@@ -72,6 +99,17 @@ impl TcpReader {
         self.channel_sender.clone()
     }
 
+    /// Number of connections currently held open by this reader.
+    pub fn connection_count(&self) -> usize {
+        self.open_connections
+    }
+
+    /// Upper bound on connections this reader will accept, so `TcpNetwork`
+    /// can load-balance new connections toward the least-loaded reader.
+    pub fn max_connections(&self) -> usize {
+        self.max_connections
+    }
+
     /// Private function for handling Reader commands
     #[inline(always)]
     fn notify(&mut self, cmd: &mut TcpReaderCommand) {
@@ -90,9 +128,20 @@ impl TcpReader {
                         None => return
                     };
 
-                    self.connections.push(TcpReaderConn::new(sock, token));
-                    // keeping index of connection inside map
-                    self.connection_keys.insert(token, self.connections.len() - 1);
+                    // at capacity: close the incoming socket instead of
+                    // accepting it and let TcpNetwork know to route elsewhere
+                    if self.open_connections >= self.max_connections {
+                        drop(sock);
+                        let _ = self.channel_tcp_net.send(TcpNetworkCommand {
+                            cmd: TcpNetworkCMD::ConnectionRejected,
+                            token: token,
+                            data: Vec::new()
+                        });
+                        continue;
+                    }
+
+                    let parser = self.parser.clone();
+                    self.insert_connection(token, TcpReaderConn::new(sock, token, parser));
                 }
             }
 
@@ -124,13 +173,25 @@ impl TcpReader {
                     // if we have this connection
                     // adding sent data to our queue for writing
                     // and making connection writable
-                    if !self.connection_keys.contains_key(&token) {
+                    let conn = match self.connections.get_mut(token.0) {
+                        Some(&mut Some(ref mut conn)) => conn,
+                        _ => continue
+                    };
+
+                    // refuse to grow the queue past the high-water mark;
+                    // drop this batch and tell TcpNetwork to pause instead
+                    let incoming: usize = cmd.data.iter().map(|buf| buf.len()).sum();
+                    if conn.queued_bytes + incoming > self.write_high_water_mark {
+                        let _ = self.channel_tcp_net.send(TcpNetworkCommand {
+                            cmd: TcpNetworkCMD::Backpressure,
+                            token: token,
+                            data: Vec::new()
+                        });
                         continue;
                     }
-                    
-                    let i = self.connection_keys[&token];
-                    self.connections[i].write_queue.append(&mut cmd.data);
-                    self.make_writable(&self.connections[i]);
+
+                    conn.enqueue_write(&mut cmd.data);
+                    TcpReader::<P>::make_writable(&self.poll, conn);
                 }
             }
         }
@@ -154,7 +215,7 @@ impl TcpReader {
                 continue
             }
 
-            for event in events.into_iter() {
+            for event in events.iter() {
                 let token = event.token();
                 if token == READER_CHANNEL_TOKEN {
                     match self.channel_receiver.try_recv() {
@@ -188,11 +249,23 @@ impl TcpReader {
         Ok(())
     }
 
+    /// Inserts a connection at the slab slot its own token indexes,
+    /// growing the vector up to it if needed.
     #[inline(always)]
-    fn make_writable(&self, conn: &TcpReaderConn) {
+    fn insert_connection(&mut self, token: Token, conn: TcpReaderConn<P>) {
+        while self.connections.len() <= token.0 {
+            self.connections.push(None);
+        }
+
+        self.connections[token.0] = Some(conn);
+        self.open_connections += 1;
+    }
+
+    #[inline(always)]
+    fn make_writable(poll: &Poll, conn: &TcpReaderConn<P>) {
         let mut r = Ready::readable();
         r.insert(Ready::writable());
-        let _ = self.poll.reregister(
+        let _ = poll.reregister(
             &conn.socket, conn.token, r,
             PollOpt::edge() | PollOpt::oneshot()
         );
@@ -200,17 +273,14 @@ impl TcpReader {
 
     #[inline(always)]
     fn close_connection(&mut self, token: Token, send_data_event: bool) {
-        // if we have this connection
-        // just removing it from our list
-        // after removing it will be automatically deatached from loop
-        if !self.connection_keys.contains_key(&token) {
-            return;
+        // if we have this connection, just freeing its slab slot;
+        // it will be automatically deatached from the poll loop once dropped
+        match self.connections.get_mut(token.0) {
+            Some(slot @ &mut Some(_)) => *slot = None,
+            _ => return
         }
 
-        let i = self.connection_keys[&token];
-
-        self.connections.remove(i);
-        self.connection_keys.remove(&token);
+        self.open_connections -= 1;
 
         // do we need to send event about connection close to
         // connection handler loop or not
@@ -225,15 +295,14 @@ impl TcpReader {
 
     #[inline(always)]
     fn readable(&mut self, token: Token) {
-        if !self.connection_keys.contains_key(&token) {
-            return;
-        }
-
-        let i = self.connection_keys[&token];
+        let conn = match self.connections.get_mut(token.0) {
+            Some(&mut Some(ref mut conn)) => conn,
+            _ => return
+        };
 
         let mut total_data: Vec<Arc<Vec<u8>>> = Vec::new();
         loop {
-            let (rd, completed) = match self.connections[i].read_data(&mut self.data_len_buf, &mut self.data_chunk) {
+            let (frames, has_more) = match conn.read_data(&mut self.data_chunk) {
                 Ok(r) => r,
                 Err(_) => {
                     // if we got error we need to close connection
@@ -242,14 +311,12 @@ impl TcpReader {
                 }
             };
 
-            // if we got some comlete data based on our API
-            // saving it for transfering to Networking loop
-            if rd.len() > 0 {
-                total_data.push(Arc::new(rd));
-            }
+            // collecting every frame the parser completed this round for
+            // transfering to the Networking loop
+            total_data.extend(frames.into_iter().map(Arc::new));
 
-            // if we completed read process, just breaking the loop
-            if !completed {
+            // if the socket had no more buffered data, stop for this event
+            if !has_more {
                 break;
             }
         }
@@ -263,6 +330,148 @@ impl TcpReader {
 
     #[inline(always)]
     fn writable(&mut self, token: Token) {
+        let conn = match self.connections.get_mut(token.0) {
+            Some(&mut Some(ref mut conn)) => conn,
+            _ => return
+        };
+
+        match conn.flush_write_queue() {
+            // queue fully drained, drop back to readable-only so we don't
+            // spin on spurious writable events
+            Ok(true) => {
+                let _ = self.poll.reregister(
+                    &conn.socket, token, Ready::readable(),
+                    PollOpt::edge() | PollOpt::oneshot()
+                );
+            }
+
+            // socket would block with data still queued, leave it armed
+            // writable for the next event
+            Ok(false) => {
+                TcpReader::<P>::make_writable(&self.poll, conn);
+            }
+
+            Err(_) => {
+                self.close_connection(token, true);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream as StdTcpStream};
+    use self::mio::tcp::TcpStream;
+    use network::tcp::frame_parser::LengthPrefixParser;
+
+    fn test_conn() -> TcpReaderConn<LengthPrefixParser> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _client = StdTcpStream::connect(addr).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        TcpReaderConn::new(TcpStream::from_stream(server).unwrap(), Token(0), LengthPrefixParser)
+    }
+
+    #[test]
+    fn closing_a_non_last_connection_does_not_shift_its_neighbours() {
+        let (s, _r) = channel::<TcpNetworkCommand>();
+        let mut reader = TcpReader::new(s, 16, 1024 * 1024);
+
+        for i in 10..13 {
+            let token = Token(i);
+            let mut conn = test_conn();
+            conn.token = token;
+            reader.insert_connection(token, conn);
+        }
+
+        // mark each connection's slot with a distinguishing byte so that if
+        // `close_connection` ever shifted indices the way `Vec::remove` used
+        // to, this would catch it cross-contaminating a neighbour
+        for i in 10..13 {
+            reader.connections[i].as_mut().unwrap().write_queue.push(Arc::new(vec![i as u8]));
+        }
+
+        // close the middle connection, not the last one
+        reader.close_connection(Token(11), false);
+
+        assert!(reader.connections[11].is_none());
+        assert_eq!(reader.connections[10].as_ref().unwrap().write_queue[0][0], 10u8);
+        assert_eq!(reader.connections[12].as_ref().unwrap().write_queue[0][0], 12u8);
+        assert_eq!(reader.connection_count(), 2);
+    }
 
+    #[test]
+    fn new_connection_past_max_connections_is_rejected() {
+        let (s, r) = channel::<TcpNetworkCommand>();
+        let mut reader = TcpReader::new(s, 1, 1024 * 1024);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let accepted_client = StdTcpStream::connect(addr).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        reader.notify(&mut TcpReaderCommand {
+            code: TcpReaderCMD::HandleNewConnection,
+            socket: vec![TcpStream::from_stream(accepted).unwrap()],
+            token: vec![Token(20)],
+            data: Vec::new()
+        });
+        assert_eq!(reader.connection_count(), 1);
+        drop(accepted_client);
+
+        // reader is now at its cap of 1; a second connection must be rejected
+        let rejected_client = StdTcpStream::connect(addr).unwrap();
+        let (rejected, _) = listener.accept().unwrap();
+        reader.notify(&mut TcpReaderCommand {
+            code: TcpReaderCMD::HandleNewConnection,
+            socket: vec![TcpStream::from_stream(rejected).unwrap()],
+            token: vec![Token(21)],
+            data: Vec::new()
+        });
+        drop(rejected_client);
+
+        assert_eq!(reader.connection_count(), 1);
+        // a rejected connection never grows the slab, so slot 21 may not
+        // exist at all; either way it must not be holding a connection
+        assert!(reader.connections.get(21).is_none_or(|slot| slot.is_none()));
+
+        match r.try_recv() {
+            Ok(cmd) => {
+                assert!(matches!(cmd.cmd, TcpNetworkCMD::ConnectionRejected));
+                assert_eq!(cmd.token, Token(21));
+            }
+            Err(_) => panic!("expected a ConnectionRejected command")
+        }
+    }
+
+    #[test]
+    fn send_data_past_high_water_mark_drops_the_batch_and_signals_backpressure() {
+        let (s, r) = channel::<TcpNetworkCommand>();
+        let mut reader = TcpReader::new(s, 16, 4);
+
+        let token = Token(30);
+        let conn = test_conn();
+        reader.insert_connection(token, conn);
+
+        // this batch alone is already over the 4 byte high-water mark
+        reader.notify(&mut TcpReaderCommand {
+            code: TcpReaderCMD::SendData,
+            socket: Vec::new(),
+            token: vec![token],
+            data: vec![Arc::new(vec![1, 2, 3, 4, 5])]
+        });
+
+        let conn = reader.connections[30].as_ref().unwrap();
+        assert!(conn.write_queue.is_empty());
+        assert_eq!(conn.queued_bytes, 0);
+
+        match r.try_recv() {
+            Ok(cmd) => {
+                assert!(matches!(cmd.cmd, TcpNetworkCMD::Backpressure));
+                assert_eq!(cmd.token, token);
+            }
+            Err(_) => panic!("expected a Backpressure command")
+        }
     }
 }